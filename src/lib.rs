@@ -1,23 +1,173 @@
+use serde::de::{Deserialize as DeserializeTrait, Deserializer};
 use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
 use std::error;
 use std::fs;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Clone, Debug)]
 pub struct Project {
     pub time: usize, // TODO this should not be pub if possible.
+    tracks: Vec<Track>,
+}
+
+/// On-disk shape of `Project`, including the legacy `sequence` field from
+/// before tracks existed. Deserializing through this lets old projects
+/// (which stored one bare sequence of waveforms) keep loading instead of
+/// silently losing their waveforms.
+#[derive(Deserialize)]
+struct ProjectRaw {
+    time: usize,
+    #[serde(default)]
+    tracks: Vec<Track>,
+    #[serde(default)]
     sequence: Vec<Waveform>,
 }
 
+impl<'de> DeserializeTrait<'de> for Project {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = ProjectRaw::deserialize(deserializer)?;
+        let tracks = if raw.tracks.is_empty() && !raw.sequence.is_empty() {
+            vec![Track {
+                gain: Track::default_gain(),
+                sequence: raw.sequence,
+            }]
+        } else {
+            raw.tracks
+        };
+        Ok(Project {
+            time: raw.time,
+            tracks,
+        })
+    }
+}
+
+/// A single mixer input: a sequence of waveforms played back at `gain`.
+/// `Project` holds several of these so playback can layer them into chords
+/// and pads instead of only ever playing one oscillator at a time.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Track {
+    #[serde(default = "Track::default_gain")]
+    pub gain: f64,
+    sequence: Vec<Waveform>,
+}
+
+impl Track {
+    fn default_gain() -> f64 {
+        1.0
+    }
+
+    pub fn sequence(&self) -> &[Waveform] {
+        &self.sequence
+    }
+
+    pub fn sequence_mut(&mut self) -> &mut [Waveform] {
+        &mut self.sequence
+    }
+}
+
+impl Default for Track {
+    fn default() -> Self {
+        Track {
+            gain: Track::default_gain(),
+            sequence: Vec::new(),
+        }
+    }
+}
+
+/// A linear ADSR (attack/decay/sustain/release) envelope applied as a
+/// per-sample gain multiplier. `attack`, `decay`, and `release` are seconds;
+/// `sustain` is the gain level held between decay and release.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Envelope {
+    pub attack: f64,
+    pub decay: f64,
+    pub sustain: f64,
+    pub release: f64,
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Envelope {
+            attack: 0.01,
+            decay: 0.1,
+            sustain: 0.8,
+            release: 0.2,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Parameters {
     #[serde(default)]
     time: usize,
+    #[serde(default = "Parameters::default_frequency")]
+    pub frequency: f64,
+    #[serde(default = "Parameters::default_amplitude")]
+    pub amplitude: f64,
+    #[serde(default)]
+    pub envelope: Envelope,
+}
+
+impl Parameters {
+    fn default_frequency() -> f64 {
+        440.0
+    }
+
+    fn default_amplitude() -> f64 {
+        1.0
+    }
 }
 
 impl Default for Parameters {
     fn default() -> Self {
-        Parameters { time: 1 }
+        Parameters {
+            time: 1,
+            frequency: Parameters::default_frequency(),
+            amplitude: Parameters::default_amplitude(),
+            envelope: Envelope::default(),
+        }
+    }
+}
+
+/// Parameters for `Waveform::Additive`: a sum of sine partials, each a
+/// multiplier on `frequency` paired with a relative amplitude.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AdditiveParameters {
+    #[serde(default = "AdditiveParameters::default_frequency")]
+    pub frequency: f64,
+    #[serde(default = "AdditiveParameters::default_amplitude")]
+    pub amplitude: f64,
+    #[serde(default = "AdditiveParameters::default_partials")]
+    pub partials: Vec<(f64, f64)>,
+    #[serde(default)]
+    pub envelope: Envelope,
+}
+
+impl AdditiveParameters {
+    fn default_frequency() -> f64 {
+        440.0
+    }
+
+    fn default_amplitude() -> f64 {
+        1.0
+    }
+
+    fn default_partials() -> Vec<(f64, f64)> {
+        vec![(0.8, 1.0), (1.0, 1.0), (1.2, 0.6), (1.7, 0.4), (2.9, 0.2)]
+    }
+}
+
+impl Default for AdditiveParameters {
+    fn default() -> Self {
+        AdditiveParameters {
+            frequency: AdditiveParameters::default_frequency(),
+            amplitude: AdditiveParameters::default_amplitude(),
+            partials: AdditiveParameters::default_partials(),
+            envelope: Envelope::default(),
+        }
     }
 }
 
@@ -28,6 +178,24 @@ pub enum Waveform {
     Square(Parameters),
     Noise(Parameters),
     NoiseSimplex(Parameters),
+    /// A stack of sine oscillators at `base_hz * multiplier`, mixed by
+    /// `amplitude` and normalized so the summed signal stays in `[-1, 1]`.
+    Additive(AdditiveParameters),
+}
+
+impl Waveform {
+    /// Returns the shared per-oscillator `Parameters`, or `None` for
+    /// variants (like `Additive`) that carry their own parameter type.
+    pub fn parameters_mut(&mut self) -> Option<&mut Parameters> {
+        match self {
+            Waveform::Sine(p)
+            | Waveform::Saw(p)
+            | Waveform::Square(p)
+            | Waveform::Noise(p)
+            | Waveform::NoiseSimplex(p) => Some(p),
+            Waveform::Additive(_) => None,
+        }
+    }
 }
 
 impl Default for Project {
@@ -48,8 +216,12 @@ impl TryFrom<String> for Project {
 }
 
 impl Project {
-    pub fn sequence(&self) -> &[Waveform] {
-        return &self.sequence;
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    pub fn tracks_mut(&mut self) -> &mut [Track] {
+        &mut self.tracks
     }
 }
 
@@ -65,4 +237,12 @@ mod tests {
         let actual = serde_json::from_str::<Waveform>(&expected_as_json).unwrap();
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn test_waveform_additive_parameters_serde() {
+        let expected = Waveform::Additive(AdditiveParameters::default());
+        let expected_as_json = serde_json::to_string(&expected).unwrap();
+        let actual = serde_json::from_str::<Waveform>(&expected_as_json).unwrap();
+        assert_eq!(expected, actual);
+    }
 }
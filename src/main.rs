@@ -4,31 +4,24 @@ use dasp::{signal, Sample, Signal};
 use eframe::{egui, epi};
 use env_logger::Env;
 use log::info;
-use serde::{Deserialize, Serialize};
+use peanosynth::{Envelope, Project, Track, Waveform};
+use ringbuf::{Consumer, RingBuffer};
 use serde_json;
-use std::sync::mpsc;
+use std::f64::consts::PI;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize)]
-pub struct Project {
-    time: usize,
-    sequence: Vec<Waveform>,
-}
+/// Samples buffered between the producer thread and the audio callback.
+/// Large enough to absorb scheduling jitter without audible latency.
+const RING_BUFFER_CAPACITY: usize = 8192;
 
-#[derive(Clone, Serialize, Deserialize)]
-pub enum Waveform {
-    Sine,
-    Saw,
-    Square,
-    Noise,
-    NoiseSimplex,
-}
+/// Destination of the "Export" button's WAV render.
+const EXPORT_PATH: &str = "export.wav";
 
-impl Default for Project {
-    fn default() -> Self {
-        let default_project = include_str!("default_project.json");
-        serde_json::from_str::<Self>(default_project).unwrap()
-    }
-}
+/// Sample rate used to render a WAV export when no output device is
+/// selected, so the project can still be rendered on a headless machine.
+const FALLBACK_SAMPLE_RATE: u32 = 44100;
 
 pub struct AudioDevice {
     device: cpal::Device,
@@ -39,117 +32,386 @@ pub struct AudioDevice {
 impl AudioDevice {
     pub fn default_device() -> Option<Self> {
         let host = cpal::default_host();
-        host.default_output_device().and_then(|dev| {
-            let config = dev.default_output_config();
-            config.ok().map(|cfg| AudioDevice {
-                device: dev,
-                sample_format: cfg.sample_format(),
-                config: cfg.into(),
-            })
+        host.default_output_device().and_then(Self::from_device)
+    }
+
+    fn from_device(device: cpal::Device) -> Option<Self> {
+        let config = device.default_output_config().ok()?;
+        Some(AudioDevice {
+            sample_format: config.sample_format(),
+            config: config.into(),
+            device,
         })
     }
+
+    pub fn name(&self) -> String {
+        self.device
+            .name()
+            .unwrap_or_else(|_| "Unknown device".to_string())
+    }
+
+    /// Enumerates the host's output devices as `(name, Device)` pairs for a
+    /// device-selection UI. Devices whose name can't be read are skipped.
+    pub fn list_output_devices() -> Vec<(String, cpal::Device)> {
+        let host = cpal::default_host();
+        host.output_devices()
+            .map(|devices| {
+                devices
+                    .filter_map(|device| device.name().ok().map(|name| (name, device)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Playback state shared between the UI thread and the producer thread that
+/// feeds the cpal output callback.
+#[derive(Default)]
+struct Playback {
+    playing: Arc<Mutex<bool>>,
+    stream: Option<cpal::Stream>,
+    producer_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Playback {
+    /// Reports whether playback is active, first reaping a stream/thread
+    /// left behind by a producer that ran to completion on its own (a
+    /// finite project finishing naturally, rather than an explicit stop).
+    fn is_playing(&mut self) -> bool {
+        let playing = *self.playing.lock().unwrap();
+        if !playing {
+            self.reap();
+        }
+        playing
+    }
+
+    fn stop(&mut self) {
+        *self.playing.lock().unwrap() = false;
+        self.reap();
+    }
+
+    fn reap(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            stream.pause().ok();
+        }
+        if let Some(handle) = self.producer_handle.take() {
+            handle.join().ok();
+        }
+    }
 }
 
 pub struct SynthApp {
     project: Project,
-    device: AudioDevice,
+    device: Option<AudioDevice>,
+    playback: Playback,
+    /// Output device names available for selection, cached at construction
+    /// and refreshed only on demand (the full host enumeration is too slow
+    /// to repeat on every egui repaint).
+    available_device_names: Vec<String>,
 }
 
 impl SynthApp {
     pub fn new() -> Self {
         Self {
             project: Project::default(),
-            device: AudioDevice::default_device().unwrap(),
+            device: AudioDevice::default_device(),
+            playback: Playback::default(),
+            available_device_names: Self::list_device_names(),
         }
     }
 
+    fn list_device_names() -> Vec<String> {
+        AudioDevice::list_output_devices()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    pub fn available_device_names(&self) -> &[String] {
+        &self.available_device_names
+    }
+
+    /// Re-enumerates output devices. Call on demand (e.g. a "Refresh"
+    /// button), not every frame.
+    pub fn refresh_devices(&mut self) {
+        self.available_device_names = Self::list_device_names();
+    }
+
     pub fn serialize_project(&self) -> String {
         serde_json::to_string(&self.project).unwrap()
     }
 
-    pub fn play(&self) -> Result<(), anyhow::Error> {
-        match self.device.sample_format {
-            cpal::SampleFormat::F32 => self.run::<f32>(),
-            cpal::SampleFormat::I16 => self.run::<i16>(),
-            cpal::SampleFormat::U16 => self.run::<u16>(),
-        }
+    pub fn is_playing(&mut self) -> bool {
+        self.playback.is_playing()
     }
 
-    fn signals_from_sequence(&self) -> impl Iterator<Item = f64> {
-        let time = self.project.time;
-        let config = &self.device.config;
-        let hz = signal::rate(config.sample_rate.0 as f64).const_hz(440.0);
-        let time_scaled = config.sample_rate.0 as usize * time;
-        self.project
-            .sequence
-            .iter()
-            .cloned()
-            .fold(
-                signal::equilibrium().take(0).collect::<Vec<f64>>(),
-                |acc, w| {
-                    let v: Vec<f64> = match w {
-                        Waveform::Sine => hz.clone().sine().take(time_scaled).collect(),
-                        Waveform::Saw => hz.clone().saw().take(time_scaled).collect(),
-                        Waveform::Square => hz.clone().square().take(time_scaled).collect(),
-                        Waveform::NoiseSimplex => {
-                            hz.clone().noise_simplex().take(time_scaled).collect()
-                        }
-                        Waveform::Noise => signal::noise(0).take(time_scaled).collect(),
-                    };
-                    acc.into_iter().chain(v.into_iter()).collect()
-                },
-            )
+    pub fn device_name(&self) -> Option<String> {
+        self.device.as_ref().map(AudioDevice::name)
+    }
+
+    /// Stops any active playback and switches to the output device named
+    /// `name`, re-reading its supported `StreamConfig` and `SampleFormat`.
+    pub fn select_device_by_name(&mut self, name: &str) {
+        if let Some((_, device)) = AudioDevice::list_output_devices()
             .into_iter()
+            .find(|(n, _)| n == name)
+        {
+            self.playback.stop();
+            self.device = AudioDevice::from_device(device);
+        }
     }
 
-    /*
-    fn build_signals(&self) -> impl Iterator<Item = f64> {
-        let time = self.project.time;
-        let config = &self.device.config;
-        let hz = signal::rate(config.sample_rate.0 as f64).const_hz(440.0);
-        let time_scaled = config.sample_rate.0 as usize * time;
-        hz.clone()
-            .sine()
-            .take(time_scaled)
-            .chain(hz.clone().saw().take(time_scaled))
-            .chain(hz.clone().square().take(time_scaled))
-            .chain(hz.clone().noise_simplex().take(time_scaled))
-            .chain(signal::noise(0).take(time_scaled))
-    } */
-
-    fn run<T>(&self) -> Result<(), anyhow::Error>
+    /// Renders the project to a 16-bit PCM WAV file at `path`, without
+    /// opening an output stream. Uses the active device's sample rate and
+    /// channel count, or falls back to `FALLBACK_SAMPLE_RATE` mono when no
+    /// device is selected, so export works on a headless machine too.
+    pub fn render_to_wav(&self, path: &str) -> Result<(), anyhow::Error> {
+        let (sample_rate, channels) = match &self.device {
+            Some(device) => (device.config.sample_rate.0, device.config.channels),
+            None => (FALLBACK_SAMPLE_RATE, 1),
+        };
+        let spec = hound::WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        let samples = signals_from_sequence(&self.project, sample_rate as f64).map(|s| s * 0.2);
+        for sample in samples {
+            let value = sample.to_sample::<i16>();
+            for _ in 0..channels {
+                writer.write_sample(value)?;
+            }
+        }
+        writer.finalize()?;
+        Ok(())
+    }
+
+    /// Starts playback if stopped, or stops it if already playing. Returns
+    /// immediately either way; synthesis happens on a background thread.
+    pub fn toggle_playback(&mut self) -> Result<(), anyhow::Error> {
+        if self.playback.is_playing() {
+            self.playback.stop();
+            return Ok(());
+        }
+        let sample_format = self
+            .device
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no output device selected"))?
+            .sample_format;
+        match sample_format {
+            cpal::SampleFormat::F32 => self.start::<f32>(),
+            cpal::SampleFormat::I16 => self.start::<i16>(),
+            cpal::SampleFormat::U16 => self.start::<u16>(),
+        }
+    }
+
+    fn start<T>(&mut self) -> Result<(), anyhow::Error>
     where
         T: cpal::Sample,
     {
-        let device = &self.device.device;
-        let config = &self.device.config;
-        // Create a signal chain to play back 1 second of each oscillator at A4.
-        let signals = self.signals_from_sequence();
-        let mut synth = signals.map(|s| s.to_sample::<f32>() * 0.2);
+        let audio_device = self
+            .device
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no output device selected"))?;
+        let device = &audio_device.device;
+        let config = &audio_device.config;
+        let sample_rate = config.sample_rate.0 as f64;
 
-        // A channel for indicating when playback has completed.
-        let (complete_tx, complete_rx) = mpsc::sync_channel(1);
+        let ring = RingBuffer::<f32>::new(RING_BUFFER_CAPACITY);
+        let (mut producer, mut consumer) = ring.split();
+
+        *self.playback.playing.lock().unwrap() = true;
+        let playing = self.playback.playing.clone();
+        let project = self.project.clone();
+        let producer_handle = thread::spawn(move || {
+            let synth =
+                signals_from_sequence(&project, sample_rate).map(|s| s.to_sample::<f32>() * 0.2);
+            for mut sample in synth {
+                if !*playing.lock().unwrap() {
+                    return;
+                }
+                while let Err(rejected) = producer.push(sample) {
+                    sample = rejected;
+                    if !*playing.lock().unwrap() {
+                        return;
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+            // Wait for the output callback to drain what is still queued.
+            while !producer.is_empty() {
+                if !*playing.lock().unwrap() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            // The project finished playing on its own; clear the flag so
+            // `is_playing()` reports false and the Play button un-sticks.
+            *playing.lock().unwrap() = false;
+        });
 
-        // Create and run the stream.
         let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
         let channels = config.channels as usize;
         let stream = device.build_output_stream(
             config,
             move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-                write_data(data, channels, &complete_tx, &mut synth)
+                write_data(data, channels, &mut consumer)
             },
             err_fn,
         )?;
         stream.play()?;
 
-        // Wait for playback to complete.
-        complete_rx.recv().unwrap();
-        stream.pause()?;
+        self.playback.stream = Some(stream);
+        self.playback.producer_handle = Some(producer_handle);
 
         Ok(())
     }
 }
 
+/// Renders every track's sequence at `sample_rate` and mixes them into a
+/// single sample stream, so tracks play simultaneously rather than one at a
+/// time.
+fn signals_from_sequence(project: &Project, sample_rate: f64) -> AudioMixer {
+    let time = project.time;
+    let sources = project
+        .tracks()
+        .iter()
+        .map(|track| {
+            let gain = track.gain;
+            let samples = track_samples(track, time, sample_rate);
+            Box::new(samples.into_iter().map(move |s| s * gain)) as Box<dyn Iterator<Item = f64> + Send>
+        })
+        .collect();
+    AudioMixer::new(sources)
+}
+
+/// Renders a single track's waveform sequence to a flat sample buffer. Each
+/// step is rendered at its own `Parameters::frequency`/`amplitude` and
+/// shaped by its ADSR envelope.
+fn track_samples(track: &Track, time: usize, sample_rate: f64) -> Vec<f64> {
+    let time_scaled = sample_rate as usize * time;
+    track
+        .sequence()
+        .iter()
+        .cloned()
+        .fold(Vec::new(), |acc, w| {
+            let v: Vec<f64> = match w {
+                Waveform::Sine(params) => {
+                    let raw = signal::rate(sample_rate)
+                        .const_hz(params.frequency)
+                        .sine()
+                        .take(time_scaled)
+                        .collect();
+                    apply_envelope(raw, &params.envelope, params.amplitude, sample_rate)
+                }
+                Waveform::Saw(params) => {
+                    let raw = signal::rate(sample_rate)
+                        .const_hz(params.frequency)
+                        .saw()
+                        .take(time_scaled)
+                        .collect();
+                    apply_envelope(raw, &params.envelope, params.amplitude, sample_rate)
+                }
+                Waveform::Square(params) => {
+                    let raw = signal::rate(sample_rate)
+                        .const_hz(params.frequency)
+                        .square()
+                        .take(time_scaled)
+                        .collect();
+                    apply_envelope(raw, &params.envelope, params.amplitude, sample_rate)
+                }
+                Waveform::NoiseSimplex(params) => {
+                    let raw = signal::rate(sample_rate)
+                        .const_hz(params.frequency)
+                        .noise_simplex()
+                        .take(time_scaled)
+                        .collect();
+                    apply_envelope(raw, &params.envelope, params.amplitude, sample_rate)
+                }
+                Waveform::Noise(params) => {
+                    let raw = signal::noise(0).take(time_scaled).collect();
+                    apply_envelope(raw, &params.envelope, params.amplitude, sample_rate)
+                }
+                Waveform::Additive(params) => {
+                    let raw =
+                        additive_samples(sample_rate, params.frequency, &params.partials, time_scaled);
+                    apply_envelope(raw, &params.envelope, params.amplitude, sample_rate)
+                }
+            };
+            acc.into_iter().chain(v.into_iter()).collect()
+        })
+}
+
+/// Scales `samples` by `amplitude` and shapes them by `envelope`'s
+/// attack/decay/sustain/release stages, applied as a per-sample gain.
+fn apply_envelope(samples: Vec<f64>, envelope: &Envelope, amplitude: f64, sample_rate: f64) -> Vec<f64> {
+    let n = samples.len();
+    samples
+        .into_iter()
+        .enumerate()
+        .map(|(i, s)| s * amplitude * envelope_gain(envelope, i, n, sample_rate))
+        .collect()
+}
+
+/// The ADSR gain at sample index `i` of an `n`-sample note.
+fn envelope_gain(envelope: &Envelope, i: usize, n: usize, sample_rate: f64) -> f64 {
+    let attack_samples = (envelope.attack * sample_rate) as usize;
+    let decay_samples = (envelope.decay * sample_rate) as usize;
+    let release_samples = (envelope.release * sample_rate) as usize;
+
+    if i < attack_samples {
+        if attack_samples == 0 {
+            1.0
+        } else {
+            i as f64 / attack_samples as f64
+        }
+    } else if i < attack_samples + decay_samples {
+        let t = (i - attack_samples) as f64 / decay_samples.max(1) as f64;
+        1.0 - t * (1.0 - envelope.sustain)
+    } else if i + release_samples < n {
+        envelope.sustain
+    } else if release_samples == 0 {
+        envelope.sustain
+    } else {
+        envelope.sustain * (n - i) as f64 / release_samples as f64
+    }
+}
+
+/// Combines several sample streams into one by summing each next frame and
+/// normalizing by the number of sources still producing a sample, so mixing
+/// tracks together never clips.
+struct AudioMixer {
+    sources: Vec<Box<dyn Iterator<Item = f64> + Send>>,
+}
+
+impl AudioMixer {
+    fn new(sources: Vec<Box<dyn Iterator<Item = f64> + Send>>) -> Self {
+        AudioMixer { sources }
+    }
+}
+
+impl Iterator for AudioMixer {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        let mut sum = 0.0;
+        let mut active = 0;
+        for source in self.sources.iter_mut() {
+            if let Some(sample) = source.next() {
+                sum += sample;
+                active += 1;
+            }
+        }
+        if active == 0 {
+            None
+        } else {
+            Some(sum / active as f64)
+        }
+    }
+}
+
 impl epi::App for SynthApp {
     fn name(&self) -> &str {
         "Synth"
@@ -157,35 +419,94 @@ impl epi::App for SynthApp {
 
     fn update(&mut self, ctx: &egui::CtxRef, frame: &mut epi::Frame<'_>) {
         egui::CentralPanel::default().show(&ctx, |ui| {
+            if self.available_device_names().is_empty() {
+                ui.label("No output devices found.");
+            } else {
+                let current_name = self.device_name();
+                let mut clicked_name = None;
+                egui::ComboBox::from_label("Output device")
+                    .selected_text(current_name.clone().unwrap_or_else(|| "Select device".to_string()))
+                    .show_ui(ui, |ui| {
+                        for name in self.available_device_names() {
+                            let selected = current_name.as_deref() == Some(name.as_str());
+                            if ui.selectable_label(selected, name).clicked() {
+                                clicked_name = Some(name.clone());
+                            }
+                        }
+                    });
+                if let Some(name) = clicked_name {
+                    self.select_device_by_name(&name);
+                }
+            }
+            if ui.button("Refresh devices").clicked() {
+                self.refresh_devices();
+            }
             ui.horizontal(|ui| {
                 ui.label("Time: ");
                 ui.add(egui::Slider::new(&mut self.project.time, 0..=60));
             });
-            if ui.button("Play").clicked() {
-                self.play().unwrap();
+            let label = if self.is_playing() { "Stop" } else { "Play" };
+            if ui.button(label).clicked() {
+                if let Err(err) = self.toggle_playback() {
+                    eprintln!("failed to toggle playback: {}", err);
+                }
+            }
+            if ui.button("Export").clicked() {
+                if let Err(err) = self.render_to_wav(EXPORT_PATH) {
+                    eprintln!("failed to export wav: {}", err);
+                }
+            }
+            ui.label(format!("Time: {} seconds", self.project.time));
+
+            for (track_index, track) in self.project.tracks_mut().iter_mut().enumerate() {
+                ui.separator();
+                ui.label(format!("Track {} (gain {:.2})", track_index, track.gain));
+                for step in track.sequence_mut() {
+                    if let Some(params) = step.parameters_mut() {
+                        ui.horizontal(|ui| {
+                            ui.add(
+                                egui::Slider::new(&mut params.frequency, 20.0..=2000.0)
+                                    .text("Hz"),
+                            );
+                            ui.add(egui::Slider::new(&mut params.amplitude, 0.0..=1.0).text("Amp"));
+                        });
+                    }
+                }
             }
-            ui.label(format!("Time: {} seconds", self.project.time))
         });
         frame.set_window_size(ctx.used_size())
     }
 }
 
-fn write_data<T>(
-    output: &mut [T],
-    channels: usize,
-    complete_tx: &mpsc::SyncSender<()>,
-    signal: &mut dyn Iterator<Item = f32>,
-) where
+/// Sums sine oscillators at `base_hz * multiplier` for each `(multiplier,
+/// amplitude)` partial, scaled by `amplitude` and normalized by the total
+/// amplitude so the mixed signal stays in `[-1, 1]`.
+fn additive_samples(sample_rate: f64, base_hz: f64, partials: &[(f64, f64)], n: usize) -> Vec<f64> {
+    let total_amplitude: f64 = partials.iter().map(|(_, amplitude)| amplitude).sum();
+    if total_amplitude == 0.0 {
+        return vec![0.0; n];
+    }
+    (0..n)
+        .map(|i| {
+            let mixed: f64 = partials
+                .iter()
+                .map(|(multiplier, amplitude)| {
+                    amplitude * (2.0 * PI * base_hz * multiplier * i as f64 / sample_rate).sin()
+                })
+                .sum();
+            mixed / total_amplitude
+        })
+        .collect()
+}
+
+/// Drains buffered samples into the output callback, writing silence on
+/// underrun instead of terminating the stream.
+fn write_data<T>(output: &mut [T], channels: usize, consumer: &mut Consumer<f32>)
+where
     T: cpal::Sample,
 {
     for frame in output.chunks_mut(channels) {
-        let sample = match signal.next() {
-            None => {
-                complete_tx.try_send(()).ok();
-                0.0
-            }
-            Some(sample) => sample,
-        };
+        let sample = consumer.pop().unwrap_or(0.0);
         let value: T = cpal::Sample::from::<f32>(&sample);
         for sample in frame.iter_mut() {
             *sample = value;